@@ -35,7 +35,7 @@ fn main() {
 
     println!("{}", tree.pretty(45));
 
-    let hello =
+    let hello: Formatted =
         group(group(group(group(text("hello") / text("a")) / text("b")) / text("c")) / text("d"));
 
     println!("{}", hello.clone().pretty(9));
@@ -0,0 +1,400 @@
+//! notugly-derive
+//! ================
+//!
+//! `#[derive(Format)]` for structs and enums, generating an [`notugly::Format`] impl
+//! that lays fields out the way you'd write by hand with [`notugly::stack`]/[`notugly::fold`]
+//! and [`notugly::bracket`].
+//!
+//! A struct's fields are wrapped in a bracketed, indented block, one field per line; like
+//! any [`notugly::bracket`], the whole block collapses onto a single line when it fits. Named
+//! fields are prefixed with their name (`field:`); an enum additionally prefixes each
+//! variant's payload with the variant's name.
+//!
+//! Container attributes (on the struct, enum, or an individual variant):
+//! - `#[format(separator = ", ")]` joins fields with `sep` on a single line instead of
+//!   stacking one per line.
+//! - `#[format(bracket("(", ")"))]` overrides the default delimiters (`{`/`}` for named
+//!   fields, `(`/`)` for tuple fields).
+//! - `#[format(indent = 2)]` overrides the default indent of 4 spaces.
+//!
+//! Field attributes:
+//! - `#[format(skip)]` omits the field from the generated layout entirely.
+//! - `#[format(display)]` formats the field with its [`std::fmt::Display`] impl (via
+//!   `notugly::text(format!("{}", field))`) instead of calling `notugly::Format::format` on it.
+//!   Use this for fields whose type doesn't implement `Format` — `i32`, `String`, `bool`, and
+//!   other primitives/stdlib types have no blanket `Format` impl, so this is how they're embedded.
+//!
+//! ## Example
+//!
+//! ```rust,ignore
+//! use notugly::*;
+//! use notugly_derive::Format;
+//!
+//! #[derive(Format)]
+//! struct Point {
+//!     #[format(display)]
+//!     x: i32,
+//!     #[format(display)]
+//!     y: i32,
+//! }
+//!
+//! #[derive(Format)]
+//! #[format(separator = ", ")]
+//! enum Shape {
+//!     Circle(#[format(display)] i32),
+//!     Rect {
+//!         #[format(display)]
+//!         w: i32,
+//!         #[format(display)]
+//!         h: i32,
+//!     },
+//! }
+//! ```
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input, Data, DeriveInput, Fields, FieldsNamed, FieldsUnnamed, GenericArgument,
+    GenericParam, Ident, LitInt, LitStr, PathArguments, Type,
+};
+
+#[proc_macro_derive(Format, attributes(format))]
+pub fn derive_format(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Per-struct/enum/variant layout options read from `#[format(...)]`.
+#[derive(Default, Clone)]
+struct ContainerOpts {
+    separator: Option<String>,
+    bracket: Option<(String, String)>,
+    indent: Option<i32>,
+}
+
+impl ContainerOpts {
+    fn parse(attrs: &[syn::Attribute]) -> syn::Result<ContainerOpts> {
+        let mut opts = ContainerOpts::default();
+        for attr in attrs {
+            if !attr.path().is_ident("format") {
+                continue;
+            }
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("separator") {
+                    let lit: LitStr = meta.value()?.parse()?;
+                    opts.separator = Some(lit.value());
+                } else if meta.path.is_ident("indent") {
+                    let lit: LitInt = meta.value()?.parse()?;
+                    opts.indent = Some(lit.base10_parse()?);
+                } else if meta.path.is_ident("bracket") {
+                    let content;
+                    syn::parenthesized!(content in meta.input);
+                    let open: LitStr = content.parse()?;
+                    content.parse::<syn::Token![,]>()?;
+                    let close: LitStr = content.parse()?;
+                    opts.bracket = Some((open.value(), close.value()));
+                } else if meta.path.is_ident("skip") {
+                    return Err(meta.error("`skip` only applies to fields, not containers"));
+                } else {
+                    return Err(meta.error("unknown `format` attribute"));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(opts)
+    }
+}
+
+/// Per-field options read from `#[format(...)]`.
+#[derive(Default)]
+struct FieldOpts {
+    skip: bool,
+    display: bool,
+}
+
+fn field_opts(attrs: &[syn::Attribute]) -> syn::Result<FieldOpts> {
+    let mut opts = FieldOpts::default();
+    for attr in attrs {
+        if !attr.path().is_ident("format") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                opts.skip = true;
+                Ok(())
+            } else if meta.path.is_ident("display") {
+                opts.display = true;
+                Ok(())
+            } else {
+                Err(meta.error("unknown `format` field attribute"))
+            }
+        })?;
+    }
+    Ok(opts)
+}
+
+/// A single field's formatted document, with an optional leading label (for named fields).
+struct FieldDoc {
+    label: Option<String>,
+    expr: TokenStream2,
+}
+
+fn default_bracket(fields: &Fields) -> (String, String) {
+    match fields {
+        Fields::Named(_) | Fields::Unit => ("{".to_string(), "}".to_string()),
+        Fields::Unnamed(_) => ("(".to_string(), ")".to_string()),
+    }
+}
+
+fn field_expr(opts: &FieldOpts, value: TokenStream2) -> TokenStream2 {
+    if opts.display {
+        quote!(::notugly::text(::std::format!("{}", #value)))
+    } else {
+        quote!(::notugly::Format::format(#value))
+    }
+}
+
+/// Collects the types of every field that isn't `#[format(skip)]`'d or `#[format(display)]`'d,
+/// across a struct's fields or (for an enum) every variant's fields: these are exactly the
+/// fields `field_expr` routes through `::notugly::Format::format`, so any of the container's
+/// own type parameters they mention need a `Format` bound on the derived impl.
+fn format_routed_field_types(data: &Data) -> syn::Result<Vec<Type>> {
+    fn from_fields(fields: &Fields, types: &mut Vec<Type>) -> syn::Result<()> {
+        for field in fields {
+            let opts = field_opts(&field.attrs)?;
+            if !opts.skip && !opts.display {
+                types.push(field.ty.clone());
+            }
+        }
+        Ok(())
+    }
+
+    let mut types = Vec::new();
+    match data {
+        Data::Struct(data) => from_fields(&data.fields, &mut types)?,
+        Data::Enum(data) => {
+            for variant in &data.variants {
+                from_fields(&variant.fields, &mut types)?;
+            }
+        }
+        Data::Union(_) => {}
+    }
+    Ok(types)
+}
+
+/// Whether `ty` mentions the generic parameter `param` anywhere within it (`T`, `Vec<T>`,
+/// `Box<T>`, `&T`, `(T, U)`, ...), used to decide which of the container's type parameters
+/// need a `Format` bound synthesized for the derived impl.
+fn type_mentions_param(ty: &Type, param: &Ident) -> bool {
+    match ty {
+        Type::Path(ty) => {
+            if ty.qself.is_none() && ty.path.is_ident(param) {
+                return true;
+            }
+            ty.path.segments.iter().any(|segment| match &segment.arguments {
+                PathArguments::AngleBracketed(args) => args.args.iter().any(|arg| match arg {
+                    GenericArgument::Type(ty) => type_mentions_param(ty, param),
+                    _ => false,
+                }),
+                PathArguments::Parenthesized(args) => {
+                    args.inputs.iter().any(|ty| type_mentions_param(ty, param))
+                        || matches!(&args.output, syn::ReturnType::Type(_, ty) if type_mentions_param(ty, param))
+                }
+                PathArguments::None => false,
+            })
+        }
+        Type::Reference(ty) => type_mentions_param(&ty.elem, param),
+        Type::Paren(ty) => type_mentions_param(&ty.elem, param),
+        Type::Group(ty) => type_mentions_param(&ty.elem, param),
+        Type::Array(ty) => type_mentions_param(&ty.elem, param),
+        Type::Slice(ty) => type_mentions_param(&ty.elem, param),
+        Type::Ptr(ty) => type_mentions_param(&ty.elem, param),
+        Type::Tuple(ty) => ty.elems.iter().any(|ty| type_mentions_param(ty, param)),
+        _ => false,
+    }
+}
+
+fn named_field_docs(fields: &FieldsNamed, accessor: impl Fn(&Ident) -> TokenStream2) -> syn::Result<Vec<FieldDoc>> {
+    let mut docs = Vec::new();
+    for field in &fields.named {
+        let opts = field_opts(&field.attrs)?;
+        if opts.skip {
+            continue;
+        }
+        let ident = field.ident.as_ref().expect("named field");
+        let value = accessor(ident);
+        docs.push(FieldDoc {
+            label: Some(format!("{ident}:")),
+            expr: field_expr(&opts, value),
+        });
+    }
+    Ok(docs)
+}
+
+fn unnamed_field_docs(
+    fields: &FieldsUnnamed,
+    accessor: impl Fn(usize) -> TokenStream2,
+) -> syn::Result<Vec<FieldDoc>> {
+    let mut docs = Vec::new();
+    for (i, field) in fields.unnamed.iter().enumerate() {
+        let opts = field_opts(&field.attrs)?;
+        if opts.skip {
+            continue;
+        }
+        let value = accessor(i);
+        docs.push(FieldDoc {
+            label: None,
+            expr: field_expr(&opts, value),
+        });
+    }
+    Ok(docs)
+}
+
+/// Wraps the given field documents in a bracketed, indented block according to `opts`,
+/// joining them with `opts.separator` on one line if given, or stacking one per line. The
+/// block is a [`notugly::bracket`] group, so it is laid out flat when it fits.
+fn layout(docs: Vec<FieldDoc>, opts: &ContainerOpts, fields: &Fields) -> TokenStream2 {
+    if docs.is_empty() {
+        return quote!(::notugly::nil());
+    }
+
+    let labelled = docs.into_iter().map(|doc| {
+        let expr = doc.expr;
+        match doc.label {
+            Some(label) => quote!(::notugly::text(#label) + (#expr)),
+            None => expr,
+        }
+    });
+
+    let body = if let Some(sep) = &opts.separator {
+        quote! {
+            ::notugly::fold(&[#(#labelled),*], |lhs, rhs| lhs & ::notugly::text(#sep) & rhs)
+        }
+    } else {
+        quote! {
+            ::notugly::stack(&[#(#labelled),*])
+        }
+    };
+
+    let (default_open, default_close) = default_bracket(fields);
+    let (open, close) = opts.bracket.clone().unwrap_or((default_open, default_close));
+    let indent = opts.indent.unwrap_or(4);
+    quote! {
+        ::notugly::bracket(#indent, #open, #body, #close)
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let opts = ContainerOpts::parse(&input.attrs)?;
+
+    let mut generics = input.generics.clone();
+    let format_routed_types = format_routed_field_types(&input.data)?;
+    let bounded_params = generics.params.iter().filter_map(|param| match param {
+        GenericParam::Type(param) => Some(param.ident.clone()),
+        _ => None,
+    });
+    let bounded_params: Vec<Ident> = bounded_params
+        .filter(|param| format_routed_types.iter().any(|ty| type_mentions_param(ty, param)))
+        .collect();
+    if !bounded_params.is_empty() {
+        let where_clause = generics.make_where_clause();
+        for param in &bounded_params {
+            where_clause.predicates.push(syn::parse_quote!(#param: ::notugly::Format));
+        }
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => {
+                let docs = named_field_docs(fields, |ident| quote!(&self.#ident))?;
+                layout(docs, &opts, &data.fields)
+            }
+            Fields::Unnamed(fields) => {
+                let docs = unnamed_field_docs(fields, |i| {
+                    let index = syn::Index::from(i);
+                    quote!(&self.#index)
+                })?;
+                layout(docs, &opts, &data.fields)
+            }
+            Fields::Unit => quote!(::notugly::nil()),
+        },
+        Data::Enum(data) => {
+            let arms = data
+                .variants
+                .iter()
+                .map(|variant| {
+                    let variant_opts = ContainerOpts::parse(&variant.attrs)?;
+                    let merged = ContainerOpts {
+                        separator: variant_opts.separator.or_else(|| opts.separator.clone()),
+                        bracket: variant_opts.bracket.or_else(|| opts.bracket.clone()),
+                        indent: variant_opts.indent.or(opts.indent),
+                    };
+                    let variant_name = &variant.ident;
+                    let label = variant_name.to_string();
+                    let arm = match &variant.fields {
+                        Fields::Unit => quote! {
+                            #name::#variant_name => ::notugly::text(#label),
+                        },
+                        Fields::Named(fields) => {
+                            let idents: Vec<&Ident> =
+                                fields.named.iter().map(|f| f.ident.as_ref().unwrap()).collect();
+                            let docs = named_field_docs(fields, |ident| quote!(#ident))?;
+                            let payload = if docs.is_empty() {
+                                quote!(::notugly::text(#label))
+                            } else {
+                                let payload = layout(docs, &merged, &variant.fields);
+                                quote!(::notugly::text(#label) + (#payload))
+                            };
+                            quote! {
+                                #name::#variant_name { #(#idents),* } => #payload,
+                            }
+                        }
+                        Fields::Unnamed(fields) => {
+                            let bindings: Vec<Ident> = (0..fields.unnamed.len())
+                                .map(|i| Ident::new(&format!("field{i}"), proc_macro2::Span::call_site()))
+                                .collect();
+                            let docs = unnamed_field_docs(fields, |i| {
+                                let binding = &bindings[i];
+                                quote!(#binding)
+                            })?;
+                            let payload = if docs.is_empty() {
+                                quote!(::notugly::text(#label))
+                            } else {
+                                let payload = layout(docs, &merged, &variant.fields);
+                                quote!(::notugly::text(#label) + (#payload))
+                            };
+                            quote! {
+                                #name::#variant_name(#(#bindings),*) => #payload,
+                            }
+                        }
+                    };
+                    Ok(arm)
+                })
+                .collect::<syn::Result<Vec<_>>>()?;
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new_spanned(
+                data.union_token,
+                "#[derive(Format)] does not support unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::notugly::Format for #name #ty_generics #where_clause {
+            fn format(&self) -> ::notugly::Document {
+                #body
+            }
+        }
+    })
+}
@@ -1,10 +1,12 @@
+use std::borrow::Cow;
 use std::collections::VecDeque;
+use std::rc::Rc;
 
 /// Describes the different ways to assemble a document.
 ///
 /// See pages 2 & 6 of [A prettier printer](https://homepages.inf.ed.ac.uk/wadler/papers/prettier/prettier.pdf),
-#[derive(Debug, Clone)]
-pub(crate) enum FormatDesc {
+#[derive(Clone)]
+pub(crate) enum FormatDesc<A> {
     /// Does nothing, the empty word
     Nil,
     /// Inserts a newline
@@ -12,18 +14,55 @@ pub(crate) enum FormatDesc {
     /// Inserts the given string
     Text(String),
     /// Indent the given document by the given number of spaces
-    Nest(i32, Box<FormatDesc>),
+    Nest(i32, Box<FormatDesc<A>>),
     /// Concatenates the given documents
-    Cat(Box<FormatDesc>, Box<FormatDesc>),
-    /// Represents a set of possible layouts. The two documents are required
-    /// to flatten to the same layout as an invariant.
-    Union(Box<FormatDesc>, Box<FormatDesc>),
+    Cat(Box<FormatDesc<A>>, Box<FormatDesc<A>>),
+    /// Offers the enclosed document's flattened layout (using `sep` in place of every
+    /// [FormatDesc::Line]) as an alternative to its unflattened layout, picked with
+    /// [Lindig's strict "fits" scan][fits] rather than by evaluating both in full.
+    Group(String, Box<FormatDesc<A>>),
+    /// Represents a set of two *arbitrary* alternative layouts, both evaluated in full
+    /// and compared with [better]. Used only by [crate::fill], whose per-pair decision
+    /// doesn't reduce to a single [FormatDesc::Group].
+    Union(Box<FormatDesc<A>>, Box<FormatDesc<A>>),
+    /// Marks the enclosed document as carrying the annotation `A`, e.g. for
+    /// syntax-highlighting purposes.
+    Annot(A, Box<FormatDesc<A>>),
+    /// Splices in the document produced by calling the closure with the current used-width
+    /// `k`, at the point [be]/[render_to] reach this node.
+    Column(Rc<dyn Fn(i32) -> FormatDesc<A>>),
+    /// Splices in the document produced by calling the closure with the current indentation
+    /// `i`, at the point [be]/[render_to] reach this node.
+    Nesting(Rc<dyn Fn(i32) -> FormatDesc<A>>),
+}
+
+impl<A: std::fmt::Debug> std::fmt::Debug for FormatDesc<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormatDesc::Nil => write!(f, "Nil"),
+            FormatDesc::Line => write!(f, "Line"),
+            FormatDesc::Text(s) => f.debug_tuple("Text").field(s).finish(),
+            FormatDesc::Nest(i, x) => f.debug_tuple("Nest").field(i).field(x).finish(),
+            FormatDesc::Cat(x, y) => f.debug_tuple("Cat").field(x).field(y).finish(),
+            FormatDesc::Group(c, x) => f.debug_tuple("Group").field(c).field(x).finish(),
+            FormatDesc::Union(x, y) => f.debug_tuple("Union").field(x).field(y).finish(),
+            FormatDesc::Annot(a, x) => f.debug_tuple("Annot").field(a).field(x).finish(),
+            FormatDesc::Column(_) => write!(f, "Column(..)"),
+            FormatDesc::Nesting(_) => write!(f, "Nesting(..)"),
+        }
+    }
 }
 
-impl FormatDesc {
-    /// Removes indentation and replaces newlines with `c`.
+impl<A: Clone + 'static> FormatDesc<A> {
+    /// Removes indentation and replaces newlines with `c`. Annotation boundaries
+    /// are left untouched: flattening only ever affects [FormatDesc::Line].
+    ///
+    /// A nested [FormatDesc::Group] keeps its own separator rather than adopting `c`,
+    /// mirroring how [FormatDesc::Group] picks its own flat text during layout. A
+    /// [FormatDesc::Column]/[FormatDesc::Nesting] isn't evaluated here: the flatten marker
+    /// is applied lazily to whatever document the closure eventually produces.
     #[inline(always)]
-    pub(crate) fn flatten_with(&self, c: &str) -> FormatDesc {
+    pub(crate) fn flatten_with(&self, c: &str) -> FormatDesc<A> {
         match self {
             FormatDesc::Nil => FormatDesc::Nil,
             FormatDesc::Line => FormatDesc::Text(c.into()),
@@ -32,54 +71,206 @@ impl FormatDesc {
             FormatDesc::Cat(x, y) => {
                 FormatDesc::Cat(Box::new(x.flatten_with(c)), Box::new(y.flatten_with(c)))
             }
+            FormatDesc::Group(sep, x) => x.flatten_with(sep),
             FormatDesc::Union(x, _) => x.flatten_with(c),
+            FormatDesc::Annot(a, x) => FormatDesc::Annot(a.clone(), Box::new(x.flatten_with(c))),
+            FormatDesc::Column(f) => {
+                let f = f.clone();
+                let c = c.to_string();
+                FormatDesc::Column(Rc::new(move |k| f(k).flatten_with(&c)))
+            }
+            FormatDesc::Nesting(f) => {
+                let f = f.clone();
+                let c = c.to_string();
+                FormatDesc::Nesting(Rc::new(move |i| f(i).flatten_with(&c)))
+            }
         }
     }
 
     /// Removes indentation and replaces newlines with a single space
     #[inline(always)]
-    pub(crate) fn flatten(&self) -> FormatDesc {
+    pub(crate) fn flatten(&self) -> FormatDesc<A> {
         self.flatten_with(" ")
     }
 
-    /// Determines the best layout that fits within `w` columns, `k` of which being already used,
-    /// and transforms it into a [ProcessedFormat] for easier printing.
+    /// Determines the best layout that fits within `width` columns, with the further
+    /// constraint that a group at indent `i` may only use `min(width, i + ribbon)` columns
+    /// before wrapping, `k` columns of which being already used, and transforms it into a
+    /// [ProcessedFormat] for easier printing. Dropping the returned [ProcessedFormat] is
+    /// safe even for a long chain: see its [Drop] impl.
+    ///
+    /// Borrows `self` rather than consuming it, so laying out a document never needs to
+    /// clone it first: a document built by [crate::stack]/[crate::spread] over many items
+    /// is typically a long chain of [FormatDesc::Cat] nodes, and cloning that chain would
+    /// reintroduce the very stack-depth problem this traversal is meant to avoid.
     #[inline(always)]
-    pub(crate) fn best(self, w: i32, k: i32) -> ProcessedFormat {
-        be(w, k, VecDeque::from([(0, self)]))
+    pub(crate) fn best_with(&self, width: i32, ribbon: i32, k: i32) -> ProcessedFormat<A> {
+        be(
+            width,
+            ribbon,
+            k,
+            VecDeque::from([Work::Doc(0, Mode::Break, Cow::Borrowed(self))]),
+        )
+    }
+
+    /// Determines the best layout that fits within `width` columns, with lines further
+    /// limited to `(width as f32 * ribbon_frac).round()` non-indentation columns, and
+    /// transforms it into a [ProcessedFormat] for easier printing.
+    #[inline(always)]
+    pub(crate) fn pretty_ribbon(&self, width: i32, ribbon_frac: f32) -> ProcessedFormat<A> {
+        let ribbon = (width as f32 * ribbon_frac).round() as i32;
+        self.best_with(width, ribbon, 0)
     }
 
     /// Determines the best layout that fits within `w` columns,
     /// and transforms it into a [ProcessedFormat] for easier printing.
     #[inline(always)]
-    pub(crate) fn pretty(self, w: i32) -> ProcessedFormat {
-        self.best(w, 0)
+    pub(crate) fn pretty(&self, w: i32) -> ProcessedFormat<A> {
+        self.pretty_ribbon(w, 1.0)
+    }
+
+    /// Chooses the best layout fitting within `w` columns and writes it straight to `out`,
+    /// without materializing the whole document as a [ProcessedFormat] first. Like [be], the
+    /// traversal itself only recurses at a [FormatDesc::Union] (as built by [crate::fill]), so
+    /// its stack usage grows with how deeply unions nest, not with the length of a
+    /// [FormatDesc::Cat] chain or the depth of nested groups. Dropping a large document afterwards is safe too: see
+    /// [crate::Document]'s [Drop] impl, which unwinds the tree iteratively rather than relying
+    /// on the default recursive drop glue.
+    pub(crate) fn render(&self, w: i32, out: &mut impl std::io::Write) -> std::io::Result<()> {
+        render_to(
+            w,
+            w,
+            0,
+            VecDeque::from([Work::Doc(0, Mode::Break, Cow::Borrowed(self))]),
+            &mut |chunk| match chunk {
+                Chunk::Text(s) => out.write_all(s.as_bytes()),
+                Chunk::Line(i) => write!(out, "\n{}", " ".repeat(i.try_into().unwrap_or(0))),
+            },
+        )
+    }
+
+    /// Same as [FormatDesc::render], but writes to a [std::fmt::Write] sink.
+    pub(crate) fn render_fmt(&self, w: i32, out: &mut impl std::fmt::Write) -> std::fmt::Result {
+        render_to(
+            w,
+            w,
+            0,
+            VecDeque::from([Work::Doc(0, Mode::Break, Cow::Borrowed(self))]),
+            &mut |chunk| match chunk {
+                Chunk::Text(s) => out.write_str(s),
+                Chunk::Line(i) => write!(out, "\n{}", " ".repeat(i.try_into().unwrap_or(0))),
+            },
+        )
+    }
+}
+
+/// Moves `node`'s direct boxed children, if any, out into `out`, replacing them in place with
+/// [FormatDesc::Nil] so that `node` itself is left shallow and drops in O(1). Used by
+/// [crate::Document]'s [Drop] impl to unwind a document tree iteratively rather than relying on
+/// the default recursive drop glue, which would walk a long [FormatDesc::Cat] chain (e.g. one
+/// built by [crate::stack]/[crate::spread] over many items) one stack frame per node.
+pub(crate) fn collect_boxed_children<A>(node: &mut FormatDesc<A>, out: &mut Vec<FormatDesc<A>>) {
+    match node {
+        FormatDesc::Nest(_, x) | FormatDesc::Group(_, x) | FormatDesc::Annot(_, x) => {
+            out.push(std::mem::replace(x.as_mut(), FormatDesc::Nil));
+        }
+        FormatDesc::Cat(x, y) | FormatDesc::Union(x, y) => {
+            out.push(std::mem::replace(x.as_mut(), FormatDesc::Nil));
+            out.push(std::mem::replace(y.as_mut(), FormatDesc::Nil));
+        }
+        FormatDesc::Nil
+        | FormatDesc::Line
+        | FormatDesc::Text(_)
+        | FormatDesc::Column(_)
+        | FormatDesc::Nesting(_) => {}
     }
 }
 
 /// Simplified representation of a formatted document
 #[derive(Debug, Clone)]
-pub enum ProcessedFormat {
+pub enum ProcessedFormat<A> {
     Nil,
-    Text(String, Box<ProcessedFormat>),
-    Line(i32, Box<ProcessedFormat>),
+    Text(String, Box<ProcessedFormat<A>>),
+    Line(i32, Box<ProcessedFormat<A>>),
+    /// Enters the scope of the annotation `A`, matched by a later [ProcessedFormat::PopAnnot].
+    PushAnnot(A, Box<ProcessedFormat<A>>),
+    /// Leaves the scope of the innermost still-open annotation.
+    PopAnnot(Box<ProcessedFormat<A>>),
 }
 
-impl std::fmt::Display for ProcessedFormat {
+impl<A> Drop for ProcessedFormat<A> {
+    /// Unwinds the `Text`/`Line`/`PushAnnot`/`PopAnnot` chain iteratively instead of letting
+    /// the default recursive drop glue walk it one stack frame per node.
+    ///
+    /// Unlike [crate::Document]'s [Drop] impl (which unwinds a `FormatDesc<A>`, a type with
+    /// no `Drop` impl of its own), `ProcessedFormat` implements `Drop` on the very type being
+    /// unwound: a node popped off `stack` is still a `ProcessedFormat`, so simply letting it
+    /// fall out of scope at the end of the loop body would re-invoke this exact `drop` on it
+    /// and recurse without bound, even though its own child has already been detached.
+    /// [std::mem::ManuallyDrop] suppresses that re-entrant call; the node's non-recursive
+    /// payload and its (by then `Nil`-holding, so O(1) to tear down) child box are instead
+    /// dropped in place explicitly.
+    fn drop(&mut self) {
+        let mut stack = vec![std::mem::replace(self, ProcessedFormat::Nil)];
+        while let Some(node) = stack.pop() {
+            let mut node = std::mem::ManuallyDrop::new(node);
+            match &mut *node {
+                ProcessedFormat::Nil => {}
+                // SAFETY: `node` is `ManuallyDrop`, so it will not itself be dropped; `b`'s
+                // child has already been moved out onto `stack` and replaced with `Nil`
+                // before `b` is torn down, so dropping it here never recurses deeper than
+                // one shallow `Nil` box. Each field is therefore dropped exactly once.
+                ProcessedFormat::Text(s, b) => unsafe {
+                    stack.push(std::mem::replace(b.as_mut(), ProcessedFormat::Nil));
+                    std::ptr::drop_in_place(s);
+                    std::ptr::drop_in_place(b);
+                },
+                ProcessedFormat::Line(_, b) => unsafe {
+                    stack.push(std::mem::replace(b.as_mut(), ProcessedFormat::Nil));
+                    std::ptr::drop_in_place(b);
+                },
+                ProcessedFormat::PushAnnot(a, b) => unsafe {
+                    stack.push(std::mem::replace(b.as_mut(), ProcessedFormat::Nil));
+                    std::ptr::drop_in_place(a);
+                    std::ptr::drop_in_place(b);
+                },
+                ProcessedFormat::PopAnnot(b) => unsafe {
+                    stack.push(std::mem::replace(b.as_mut(), ProcessedFormat::Nil));
+                    std::ptr::drop_in_place(b);
+                },
+            }
+        }
+    }
+}
+
+impl<A> std::fmt::Display for ProcessedFormat<A> {
     /// Corresponds to the `layout` function of
-    /// [A prettier printer](https://homepages.inf.ed.ac.uk/wadler/papers/prettier/prettier.pdf)
+    /// [A prettier printer](https://homepages.inf.ed.ac.uk/wadler/papers/prettier/prettier.pdf).
+    /// Annotations carry no textual representation here; use [ProcessedFormat::render_annotated]
+    /// or [ProcessedFormat::ansi] to act on them.
+    ///
+    /// Walks the `Text`/`Line` chain with an explicit loop rather than recursing into `x`,
+    /// so the call stack doesn't grow with the length of the document.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            ProcessedFormat::Nil => Ok(()),
-            ProcessedFormat::Text(s, x) => write!(f, "{s}{x}"),
-            ProcessedFormat::Line(i, x) => {
-                write!(f, "\n{}{}", " ".repeat((*i).try_into().unwrap_or(0)), x)
+        let mut cur = self;
+        loop {
+            cur = match cur {
+                ProcessedFormat::Nil => return Ok(()),
+                ProcessedFormat::Text(s, x) => {
+                    write!(f, "{s}")?;
+                    x
+                }
+                ProcessedFormat::Line(i, x) => {
+                    write!(f, "\n{}", " ".repeat((*i).try_into().unwrap_or(0)))?;
+                    x
+                }
+                ProcessedFormat::PushAnnot(_, x) | ProcessedFormat::PopAnnot(x) => x,
             }
         }
     }
 }
 
-impl ProcessedFormat {
+impl<A> ProcessedFormat<A> {
     /// Simply says if the documents fits in the remaining space or not.
     pub(crate) fn fits(&self, w: i32) -> bool {
         if w < 0 {
@@ -89,46 +280,519 @@ impl ProcessedFormat {
         match self {
             ProcessedFormat::Nil | ProcessedFormat::Line(_, _) => true,
             ProcessedFormat::Text(s, x) => x.fits(w - s.len() as i32),
+            ProcessedFormat::PushAnnot(_, x) | ProcessedFormat::PopAnnot(x) => x.fits(w),
+        }
+    }
+
+    /// Walks the processed tree, reporting text, line breaks and annotation
+    /// boundaries as [Event]s. Each [Event::Begin] is matched by exactly one
+    /// later [Event::End], possibly nested.
+    pub fn render_annotated(&self, f: &mut impl FnMut(Event<'_, A>)) {
+        match self {
+            ProcessedFormat::Nil => {}
+            ProcessedFormat::Text(s, x) => {
+                f(Event::Text(s));
+                x.render_annotated(f);
+            }
+            ProcessedFormat::Line(i, x) => {
+                f(Event::Line(*i));
+                x.render_annotated(f);
+            }
+            ProcessedFormat::PushAnnot(a, x) => {
+                f(Event::Begin(a));
+                x.render_annotated(f);
+            }
+            ProcessedFormat::PopAnnot(x) => {
+                f(Event::End);
+                x.render_annotated(f);
+            }
+        }
+    }
+
+    /// Renders the document to a [String], mapping each annotation to an ANSI
+    /// escape sequence via [Into<Style>]. On [Event::End], restores the enclosing
+    /// annotation's style (if any) rather than resetting unconditionally, so that
+    /// closing a nested [crate::annotate] doesn't also discard an outer one.
+    pub fn ansi(&self) -> String
+    where
+        A: Clone + Into<Style>,
+    {
+        let mut out = String::new();
+        let mut styles: Vec<Style> = Vec::new();
+        self.render_annotated(&mut |event| match event {
+            Event::Text(s) => out.push_str(s),
+            Event::Line(i) => {
+                out.push('\n');
+                out.push_str(&" ".repeat(i.try_into().unwrap_or(0)));
+            }
+            Event::Begin(a) => {
+                let style = a.clone().into();
+                out.push_str(&style.escape_code());
+                styles.push(style);
+            }
+            Event::End => {
+                styles.pop();
+                out.push_str(Style::RESET);
+                if let Some(style) = styles.last() {
+                    out.push_str(&style.escape_code());
+                }
+            }
+        });
+        out
+    }
+}
+
+/// An event emitted by [ProcessedFormat::render_annotated] while walking a processed document.
+pub enum Event<'a, A> {
+    /// Enters the scope of an annotation.
+    Begin(&'a A),
+    /// A run of text to emit verbatim.
+    Text(&'a str),
+    /// A line break, followed by the given number of indentation spaces.
+    Line(i32),
+    /// Leaves the scope of the innermost still-open annotation.
+    End,
+}
+
+/// Minimal ANSI SGR styling, used as the target of [ProcessedFormat::ansi].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bold: bool,
+    pub underline: bool,
+}
+
+/// The 8 standard ANSI foreground colors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+}
+
+impl Style {
+    pub(crate) const RESET: &'static str = "\x1b[0m";
+
+    pub(crate) fn escape_code(self) -> String {
+        let mut codes = Vec::new();
+        if self.bold {
+            codes.push(1);
+        }
+        if self.underline {
+            codes.push(4);
+        }
+        if let Some(c) = self.fg {
+            codes.push(30 + c as u8);
+        }
+        if codes.is_empty() {
+            return String::new();
         }
+        format!(
+            "\x1b[{}m",
+            codes
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(";")
+        )
     }
 }
 
-/// Given a list of (indentation, document) pairs, chooses the best layout possible for the given width `w`
-/// and remaining space `k`.
-pub(crate) fn be(w: i32, k: i32, mut z: VecDeque<(i32, FormatDesc)>) -> ProcessedFormat {
-    match z.pop_front() {
-        None => ProcessedFormat::Nil,
-        Some((_, FormatDesc::Nil)) => be(w, k, z),
-        Some((i, FormatDesc::Cat(x, y))) => {
-            z.push_front((i, *y));
-            z.push_front((i, *x));
-            be(w, k, z)
+/// Whether a [FormatDesc::Group] currently being laid out was picked to render flat
+/// (in which case a [FormatDesc::Line] becomes `sep`) or broken onto its own line.
+#[derive(Debug, Clone)]
+pub(crate) enum Mode {
+    Break,
+    Flat(String),
+}
+
+/// An item of the explicit work stack driving [be] and [render_to]: a document to lay out at
+/// a given indentation and [Mode], or a marker recording that the innermost open annotation
+/// should be closed once everything pushed before it is emitted.
+///
+/// The document is [Cow]: most of the time it's borrowed straight from the original tree (so
+/// walking a document never needs to clone it), but [FormatDesc::Column]/[FormatDesc::Nesting]
+/// splice in a freshly-produced, owned document at the point they're evaluated.
+#[derive(Clone)]
+pub(crate) enum Work<'a, A: Clone> {
+    Doc(i32, Mode, Cow<'a, FormatDesc<A>>),
+    PopAnnot,
+}
+
+/// A single resolved piece of output: a run of text, a line break followed by `i` spaces
+/// of indentation, or the start or end of an annotation's scope.
+enum Piece<A> {
+    Text(String),
+    Line(i32),
+    PushAnnot(A),
+    PopAnnot,
+}
+
+/// The maximum column a line may reach at indent `i`: the lesser of the page `width` and the
+/// indent plus the `ribbon` (the maximum number of non-indentation columns on a line). Used by
+/// [be]/[render_to] to compute each group's own fit budget, per [FormatDesc::pretty_ribbon].
+#[inline(always)]
+fn effective_width(width: i32, ribbon: i32, i: i32) -> i32 {
+    width.min(i + ribbon)
+}
+
+/// Given a work stack of (indentation, mode, document) triples, chooses the best layout
+/// possible for the given `width`/`ribbon` (see [FormatDesc::pretty_ribbon]), `k` columns of
+/// the current line being already used.
+///
+/// Documents resolve to a straight-line sequence of [Piece]s far more often than they branch,
+/// so this drains `z` with an explicit `loop` instead of recursing once per node. A
+/// [FormatDesc::Group] is resolved in place via [fits] (Lindig's strict scan, O(w) and free of
+/// any tree cloning); only the legacy [FormatDesc::Union] (used by [crate::fill]) still falls
+/// back to evaluating both alternatives in full, and even then only clones the pending work
+/// items: borrowed ones just copy a reference, and only a document spliced in by
+/// [FormatDesc::Column]/[FormatDesc::Nesting] is cloned as a tree.
+pub(crate) fn be<'a, A: Clone>(
+    width: i32,
+    ribbon: i32,
+    mut k: i32,
+    mut z: VecDeque<Work<'a, A>>,
+) -> ProcessedFormat<A> {
+    let mut pieces = Vec::new();
+    loop {
+        match z.pop_front() {
+            None => break,
+            Some(Work::PopAnnot) => pieces.push(Piece::PopAnnot),
+            Some(Work::Doc(i, mode, doc)) => match unbox(doc) {
+                Unboxed::Nil => {}
+                Unboxed::Line => match mode {
+                    Mode::Break => {
+                        k = i;
+                        pieces.push(Piece::Line(i));
+                    }
+                    Mode::Flat(sep) => {
+                        k += sep.len() as i32;
+                        pieces.push(Piece::Text(sep));
+                    }
+                },
+                Unboxed::Text(s) => {
+                    k += s.len() as i32;
+                    pieces.push(Piece::Text(s));
+                }
+                Unboxed::Cat(x, y) => {
+                    z.push_front(Work::Doc(i, mode.clone(), y));
+                    z.push_front(Work::Doc(i, mode, x));
+                }
+                Unboxed::Nest(j, x) => {
+                    z.push_front(Work::Doc(i + j, mode, x));
+                }
+                Unboxed::Annot(a, x) => {
+                    z.push_front(Work::PopAnnot);
+                    z.push_front(Work::Doc(i, mode, x));
+                    pieces.push(Piece::PushAnnot(a));
+                }
+                Unboxed::Group(sep, x) => match mode {
+                    Mode::Flat(_) => z.push_front(Work::Doc(i, Mode::Flat(sep), x)),
+                    Mode::Break => {
+                        let eff = effective_width(width, ribbon, i);
+                        if fits(eff - k, i, k, Mode::Flat(sep.clone()), x.as_ref(), z.iter()) {
+                            z.push_front(Work::Doc(i, Mode::Flat(sep), x));
+                        } else {
+                            z.push_front(Work::Doc(i, Mode::Break, x));
+                        }
+                    }
+                },
+                Unboxed::Union(x, y) => {
+                    let eff = effective_width(width, ribbon, i);
+                    let mut z1 = z;
+                    let mut z2 = z1.clone();
+                    z1.push_front(Work::Doc(i, mode.clone(), x));
+                    z2.push_front(Work::Doc(i, mode, y));
+                    let rest = better(
+                        eff,
+                        k,
+                        be(width, ribbon, k, z1),
+                        be(width, ribbon, k, z2),
+                    );
+                    return pieces.into_iter().rev().fold(rest, Piece::wrap);
+                }
+                Unboxed::Column(f) => {
+                    z.push_front(Work::Doc(i, mode, Cow::Owned(f(k))));
+                }
+                Unboxed::Nesting(f) => {
+                    z.push_front(Work::Doc(i, mode, Cow::Owned(f(i))));
+                }
+            },
         }
-        Some((i, FormatDesc::Nest(j, x))) => {
-            z.push_front((i + j, *x));
-            be(w, k, z)
+    }
+    pieces
+        .into_iter()
+        .rev()
+        .fold(ProcessedFormat::Nil, Piece::wrap)
+}
+
+impl<A> Piece<A> {
+    #[inline(always)]
+    fn wrap(rest: ProcessedFormat<A>, piece: Piece<A>) -> ProcessedFormat<A> {
+        match piece {
+            Piece::Text(s) => ProcessedFormat::Text(s, Box::new(rest)),
+            Piece::Line(i) => ProcessedFormat::Line(i, Box::new(rest)),
+            Piece::PushAnnot(a) => ProcessedFormat::PushAnnot(a, Box::new(rest)),
+            Piece::PopAnnot => ProcessedFormat::PopAnnot(Box::new(rest)),
+        }
+    }
+}
+
+/// A [FormatDesc] popped off a [Cow], with its boxed children (if any) given back as `Cow`s of
+/// the same lifetime: [Cow::Borrowed] children stay zero-copy, [Cow::Owned] children (there
+/// only because some enclosing [FormatDesc::Column]/[FormatDesc::Nesting] was just evaluated)
+/// are moved out rather than cloned.
+enum Unboxed<'a, A: Clone> {
+    Nil,
+    Line,
+    Text(String),
+    Nest(i32, Cow<'a, FormatDesc<A>>),
+    Cat(Cow<'a, FormatDesc<A>>, Cow<'a, FormatDesc<A>>),
+    Group(String, Cow<'a, FormatDesc<A>>),
+    Union(Cow<'a, FormatDesc<A>>, Cow<'a, FormatDesc<A>>),
+    Annot(A, Cow<'a, FormatDesc<A>>),
+    Column(Rc<dyn Fn(i32) -> FormatDesc<A>>),
+    Nesting(Rc<dyn Fn(i32) -> FormatDesc<A>>),
+}
+
+#[inline(always)]
+fn unbox<A: Clone>(doc: Cow<'_, FormatDesc<A>>) -> Unboxed<'_, A> {
+    match doc {
+        Cow::Borrowed(FormatDesc::Nil) => Unboxed::Nil,
+        Cow::Owned(FormatDesc::Nil) => Unboxed::Nil,
+        Cow::Borrowed(FormatDesc::Line) => Unboxed::Line,
+        Cow::Owned(FormatDesc::Line) => Unboxed::Line,
+        Cow::Borrowed(FormatDesc::Text(s)) => Unboxed::Text(s.clone()),
+        Cow::Owned(FormatDesc::Text(s)) => Unboxed::Text(s),
+        Cow::Borrowed(FormatDesc::Nest(j, x)) => Unboxed::Nest(*j, Cow::Borrowed(x)),
+        Cow::Owned(FormatDesc::Nest(j, x)) => Unboxed::Nest(j, Cow::Owned(*x)),
+        Cow::Borrowed(FormatDesc::Cat(x, y)) => {
+            Unboxed::Cat(Cow::Borrowed(x), Cow::Borrowed(y))
+        }
+        Cow::Owned(FormatDesc::Cat(x, y)) => Unboxed::Cat(Cow::Owned(*x), Cow::Owned(*y)),
+        Cow::Borrowed(FormatDesc::Group(sep, x)) => {
+            Unboxed::Group(sep.clone(), Cow::Borrowed(x))
         }
-        Some((_, FormatDesc::Text(s))) => {
-            let slen = s.len();
-            ProcessedFormat::Text(s, Box::new(be(w, slen as i32 + k, z)))
+        Cow::Owned(FormatDesc::Group(sep, x)) => Unboxed::Group(sep, Cow::Owned(*x)),
+        Cow::Borrowed(FormatDesc::Union(x, y)) => {
+            Unboxed::Union(Cow::Borrowed(x), Cow::Borrowed(y))
         }
-        Some((i, FormatDesc::Line)) => ProcessedFormat::Line(i, Box::new(be(w, i, z))),
-        Some((i, FormatDesc::Union(x, y))) => {
-            let mut z1 = z;
-            let mut z2 = z1.clone();
-            z1.push_front((i, *x));
-            z2.push_front((i, *y));
-            better(w, k, be(w, k, z1), be(w, k, z2))
+        Cow::Owned(FormatDesc::Union(x, y)) => Unboxed::Union(Cow::Owned(*x), Cow::Owned(*y)),
+        Cow::Borrowed(FormatDesc::Annot(a, x)) => {
+            Unboxed::Annot(a.clone(), Cow::Borrowed(x))
+        }
+        Cow::Owned(FormatDesc::Annot(a, x)) => Unboxed::Annot(a, Cow::Owned(*x)),
+        Cow::Borrowed(FormatDesc::Column(f)) => Unboxed::Column(f.clone()),
+        Cow::Owned(FormatDesc::Column(f)) => Unboxed::Column(f),
+        Cow::Borrowed(FormatDesc::Nesting(f)) => Unboxed::Nesting(f.clone()),
+        Cow::Owned(FormatDesc::Nesting(f)) => Unboxed::Nesting(f),
+    }
+}
+
+/// Lindig's strict "fits" scan: checks whether `doc`, laid out in `mode` and followed by
+/// whatever remains in `rest`, stays within `w` columns before the next hard line break.
+///
+/// This never evaluates an alternative layout: it accumulates text widths in a small local
+/// stack and bails out as soon as `w` goes negative, returning `true` the instant it reaches a
+/// [Mode::Break] [FormatDesc::Line] or runs out of work. `rest`'s underlying deque is only
+/// iterated, never cloned (cloning an individual [Cow] it yields is cheap in the overwhelming
+/// common case, since [Cow::Borrowed] just copies a reference). A nested [FormatDesc::Group] is
+/// always scanned as if it had been chosen flat, since that's the most it could possibly add to
+/// the current line; a nested [FormatDesc::Union] is scanned via its first (more packed)
+/// alternative, which is how [crate::fill] always constructs it. [FormatDesc::Column]/
+/// [FormatDesc::Nesting] are evaluated eagerly against the running column/indent so the scan
+/// sees their actual contribution to the line's width.
+fn fits<'a, A: Clone>(
+    w0: i32,
+    i0: i32,
+    k0: i32,
+    mode0: Mode,
+    doc0: &'a FormatDesc<A>,
+    mut rest: impl Iterator<Item = &'a Work<'a, A>>,
+) -> bool {
+    let mut w = w0;
+    let mut k = k0;
+    let mut local: Vec<(i32, Mode, Cow<'a, FormatDesc<A>>)> = vec![(i0, mode0, Cow::Borrowed(doc0))];
+    loop {
+        if w < 0 {
+            return false;
+        }
+        let (i, mode, doc) = loop {
+            if let Some(item) = local.pop() {
+                break item;
+            }
+            match rest.next() {
+                None => return true,
+                Some(Work::PopAnnot) => continue,
+                Some(Work::Doc(i, m, d)) => break (*i, m.clone(), d.clone()),
+            }
+        };
+        match unbox(doc) {
+            Unboxed::Nil => {}
+            Unboxed::Text(s) => {
+                w -= s.len() as i32;
+                k += s.len() as i32;
+            }
+            Unboxed::Line => match &mode {
+                Mode::Flat(sep) => {
+                    w -= sep.len() as i32;
+                    k += sep.len() as i32;
+                }
+                Mode::Break => return true,
+            },
+            Unboxed::Cat(x, y) => {
+                local.push((i, mode.clone(), y));
+                local.push((i, mode, x));
+            }
+            Unboxed::Nest(j, x) => local.push((i + j, mode, x)),
+            Unboxed::Annot(_, x) => local.push((i, mode, x)),
+            Unboxed::Group(sep, x) => local.push((i, Mode::Flat(sep), x)),
+            Unboxed::Union(x, _) => local.push((i, mode, x)),
+            Unboxed::Column(f) => local.push((i, mode, Cow::Owned(f(k)))),
+            Unboxed::Nesting(f) => local.push((i, mode, Cow::Owned(f(i)))),
+        }
+    }
+}
+
+/// A chunk of output as produced by [render_to] or replayed from an already-resolved
+/// [ProcessedFormat] by [render_processed]. Annotations are dropped: neither
+/// [FormatDesc::render] nor [FormatDesc::render_fmt] know how to turn an arbitrary `A` into text.
+enum Chunk<'a> {
+    Text(&'a str),
+    Line(i32),
+}
+
+/// Same traversal as [be], but instead of building a [ProcessedFormat], it hands each
+/// resolved [Chunk] to `emit` as soon as it is decided, writing straight out of the borrowed
+/// document with no intermediate [ProcessedFormat]. Falls back to [be]/[render_processed] at
+/// a [FormatDesc::Union], exactly like [be] falls back to recursing into itself there.
+fn render_to<'a, A: Clone, E>(
+    width: i32,
+    ribbon: i32,
+    mut k: i32,
+    mut z: VecDeque<Work<'a, A>>,
+    emit: &mut impl FnMut(Chunk<'_>) -> Result<(), E>,
+) -> Result<(), E> {
+    loop {
+        match z.pop_front() {
+            None => return Ok(()),
+            Some(Work::PopAnnot) => {}
+            Some(Work::Doc(i, mode, doc)) => match unbox(doc) {
+                Unboxed::Nil => {}
+                Unboxed::Line => match mode {
+                    Mode::Break => {
+                        k = i;
+                        emit(Chunk::Line(i))?;
+                    }
+                    Mode::Flat(sep) => {
+                        k += sep.len() as i32;
+                        emit(Chunk::Text(&sep))?;
+                    }
+                },
+                Unboxed::Text(s) => {
+                    k += s.len() as i32;
+                    emit(Chunk::Text(&s))?;
+                }
+                Unboxed::Cat(x, y) => {
+                    z.push_front(Work::Doc(i, mode.clone(), y));
+                    z.push_front(Work::Doc(i, mode, x));
+                }
+                Unboxed::Nest(j, x) => {
+                    z.push_front(Work::Doc(i + j, mode, x));
+                }
+                Unboxed::Annot(_, x) => {
+                    z.push_front(Work::Doc(i, mode, x));
+                }
+                Unboxed::Group(sep, x) => match mode {
+                    Mode::Flat(_) => z.push_front(Work::Doc(i, Mode::Flat(sep), x)),
+                    Mode::Break => {
+                        let eff = effective_width(width, ribbon, i);
+                        if fits(eff - k, i, k, Mode::Flat(sep.clone()), x.as_ref(), z.iter()) {
+                            z.push_front(Work::Doc(i, Mode::Flat(sep), x));
+                        } else {
+                            z.push_front(Work::Doc(i, Mode::Break, x));
+                        }
+                    }
+                },
+                Unboxed::Union(x, y) => {
+                    let eff = effective_width(width, ribbon, i);
+                    let mut z1 = z;
+                    let mut z2 = z1.clone();
+                    z1.push_front(Work::Doc(i, mode.clone(), x));
+                    z2.push_front(Work::Doc(i, mode, y));
+                    let rest = better(
+                        eff,
+                        k,
+                        be(width, ribbon, k, z1),
+                        be(width, ribbon, k, z2),
+                    );
+                    return render_processed(&rest, emit);
+                }
+                Unboxed::Column(f) => {
+                    z.push_front(Work::Doc(i, mode, Cow::Owned(f(k))));
+                }
+                Unboxed::Nesting(f) => {
+                    z.push_front(Work::Doc(i, mode, Cow::Owned(f(i))));
+                }
+            },
+        }
+    }
+}
+
+/// Replays an already-resolved [ProcessedFormat] into `emit`, iteratively: used by
+/// [render_to] once a [FormatDesc::Union] forces it to fall back to [be].
+fn render_processed<A, E>(
+    pf: &ProcessedFormat<A>,
+    emit: &mut impl FnMut(Chunk<'_>) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut cur = pf;
+    loop {
+        cur = match cur {
+            ProcessedFormat::Nil => return Ok(()),
+            ProcessedFormat::Text(s, x) => {
+                emit(Chunk::Text(s))?;
+                x
+            }
+            ProcessedFormat::Line(i, x) => {
+                emit(Chunk::Line(*i))?;
+                x
+            }
+            ProcessedFormat::PushAnnot(_, x) | ProcessedFormat::PopAnnot(x) => x,
         }
     }
 }
 
 /// Returns `x` if it fits, othewise return `y`. Both documents are supposed to flatten to the same layout.
 #[inline(always)]
-pub(crate) fn better(w: i32, k: i32, x: ProcessedFormat, y: ProcessedFormat) -> ProcessedFormat {
+pub(crate) fn better<A>(
+    w: i32,
+    k: i32,
+    x: ProcessedFormat<A>,
+    y: ProcessedFormat<A>,
+) -> ProcessedFormat<A> {
     if x.fits(w - k) {
         x
     } else {
         y
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Regression test for a bug where every `ProcessedFormat` drop, including this most
+    /// trivial one, overflowed the stack: `Drop::drop` detached a node's child but let the
+    /// node itself fall out of scope normally, which re-entered `Drop::drop` on the very
+    /// same type and recursed without end.
+    #[test]
+    fn dropping_a_processed_format_does_not_overflow_the_stack() {
+        let pf: ProcessedFormat<()> = FormatDesc::Text("x".into()).pretty(80);
+        drop(pf);
+    }
+}
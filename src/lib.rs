@@ -13,6 +13,8 @@
 //! - [cat] to concatenate two documents
 //! - [group] and [group_with] to add the flattened layout as an alternative.
 //! - [fold], [spread], [stack] and [fill] to collapse a list of documents in various ways
+//! - [annotate] to attach a semantic annotation (e.g. a syntax-highlighting category) to a document
+//! - [column], [nesting] and [align] to make layout depend on the current output column or indentation
 //!
 //!
 //! To make it easier to define a structure, some operators are defined:
@@ -51,154 +53,284 @@
 //! }
 //! ```
 mod format;
-use format::{FormatDesc, ProcessedFormat};
+pub use format::{Color, Event, ProcessedFormat, Style};
+use format::FormatDesc;
+use std::rc::Rc;
 
-/// Opaque type representating a set of possible layouts for a document.
+/// Opaque type representating a set of possible layouts for a document, optionally
+/// annotated with values of type `A` (e.g. syntax-highlight categories). Most users
+/// can ignore `A` and work with the default `Document` (aliasing `Document<()>`).
 #[derive(Clone, Debug)]
-pub struct Document(FormatDesc);
+pub struct Document<A = ()>(FormatDesc<A>);
 
-impl Document {
-    pub(crate) fn map(self, f: impl FnOnce(FormatDesc) -> FormatDesc) -> Document {
-        Document(f(self.0))
+/// Convenience alias for the common case of a [Document] carrying no annotations.
+/// Rust's default type parameters only get applied when a type is written out
+/// (as in this alias, or a `-> Document` return type), not as an inference fallback,
+/// so an unannotated `let` binding built purely from free functions like [text]/[group]
+/// still needs a type somewhere to pin `A`; reach for `Formatted` there.
+pub type Formatted = Document<()>;
+
+impl<A> Drop for Document<A> {
+    /// Unwinds the document tree iteratively, replacing each boxed child with
+    /// [FormatDesc::Nil] as it's visited, before the default (recursive) drop glue for
+    /// [FormatDesc] gets a chance to run on it. A document built by [stack]/[spread] over
+    /// many items is a long chain of [FormatDesc::Cat] nodes; dropping that recursively
+    /// (one stack frame per node) is exactly the depth [FormatDesc::best_with]/`render_to`
+    /// were written to avoid during layout, and this avoids it during drop too.
+    fn drop(&mut self) {
+        let mut stack = vec![std::mem::replace(&mut self.0, FormatDesc::Nil)];
+        while let Some(mut node) = stack.pop() {
+            format::collect_boxed_children(&mut node, &mut stack);
+        }
+    }
+}
+
+impl<A> Document<A> {
+    /// Moves the underlying [FormatDesc] out, leaving `self` holding [FormatDesc::Nil].
+    /// This is how code consumes a `Document` by value: `self.0` can't be moved out
+    /// directly since [Document] has a custom [Drop] impl.
+    pub(crate) fn into_inner(mut self) -> FormatDesc<A> {
+        std::mem::replace(&mut self.0, FormatDesc::Nil)
     }
+}
 
-    pub(crate) fn flatten_with(&self, c: &str) -> Document {
-        self.clone().map(|x| x.flatten_with(c))
+impl<A: Clone + 'static> Document<A> {
+    pub(crate) fn map(self, f: impl FnOnce(FormatDesc<A>) -> FormatDesc<A>) -> Document<A> {
+        Document(f(self.into_inner()))
     }
 
-    pub(crate) fn flatten(&self) -> Document {
+    pub(crate) fn flatten(&self) -> Document<A> {
         self.clone().map(|x| x.flatten())
     }
+
+    /// Chooses the best layout fitting within `w` columns. This inherent method (rather
+    /// than the one from [Format]) is what resolves when called directly on a `Document`,
+    /// and unlike the trait's, it lays out `self` by reference: no need to clone the whole
+    /// document first, which matters since a document built by [stack]/[spread]/[fill] over
+    /// many items is a long chain that cloning would walk one stack frame at a time.
+    pub fn pretty(&self, w: i32) -> ProcessedFormat<A> {
+        self.0.pretty(w)
+    }
+
+    /// Chooses the best layout fitting within `width` columns, with the further constraint
+    /// that no line may use more than `(width as f32 * ribbon_frac).round()` non-indentation
+    /// columns: a group nested `i` spaces deep is laid out as if the page were only
+    /// `min(width, i + ribbon)` columns wide, so deeply indented blocks don't get packed with
+    /// long runs of text against the right margin. `pretty(w)` is `pretty_ribbon(w, 1.0)`.
+    pub fn pretty_ribbon(&self, width: i32, ribbon_frac: f32) -> ProcessedFormat<A> {
+        self.0.pretty_ribbon(width, ribbon_frac)
+    }
+
+    /// Chooses the best layout fitting within `w` columns and writes it straight to `out`,
+    /// without materializing an intermediate [ProcessedFormat]. Prefer this over
+    /// `.pretty(w).to_string()` for large documents: it's both faster and doesn't build up
+    /// an extra tree for [ProcessedFormat]'s [Drop] impl to unwind afterwards (though that unwinding
+    /// is itself iterative, so either way dropping a large document stays stack-safe).
+    pub fn render(&self, w: i32, out: &mut impl std::io::Write) -> std::io::Result<()> {
+        self.0.render(w, out)
+    }
+
+    /// Same as [Document::render], but writes to a [std::fmt::Write] sink.
+    pub fn render_fmt(&self, w: i32, out: &mut impl std::fmt::Write) -> std::fmt::Result {
+        self.0.render_fmt(w, out)
+    }
 }
 
 /// Given the `format` method, telling how to turn a `Self` into a [Document],
 /// provides the `pretty` method to choose the best layout.
-pub trait Format {
-    fn format(&self) -> Document;
+pub trait Format<A: 'static = ()> {
+    fn format(&self) -> Document<A>;
 
-    fn pretty(&self, w: i32) -> ProcessedFormat {
+    fn pretty(&self, w: i32) -> ProcessedFormat<A>
+    where
+        A: Clone,
+    {
         self.format().0.pretty(w)
     }
+
+    /// Same as [Document::pretty_ribbon], for any [Format] implementer.
+    fn pretty_ribbon(&self, width: i32, ribbon_frac: f32) -> ProcessedFormat<A>
+    where
+        A: Clone,
+    {
+        self.format().0.pretty_ribbon(width, ribbon_frac)
+    }
+
+    /// Chooses the best layout fitting within `w` columns and writes it straight to `out`,
+    /// without materializing an intermediate [ProcessedFormat].
+    fn render(&self, w: i32, out: &mut impl std::io::Write) -> std::io::Result<()>
+    where
+        A: Clone,
+    {
+        self.format().0.render(w, out)
+    }
+
+    /// Same as [Format::render], but writes to a [std::fmt::Write] sink.
+    fn render_fmt(&self, w: i32, out: &mut impl std::fmt::Write) -> std::fmt::Result
+    where
+        A: Clone,
+    {
+        self.format().0.render_fmt(w, out)
+    }
 }
 
-impl Format for Document {
-    fn format(&self) -> Document {
+impl<A: Clone + 'static> Format<A> for Document<A> {
+    fn format(&self) -> Document<A> {
         self.clone()
     }
 }
 
 /// Produces a null document.
 #[inline(always)]
-pub fn nil() -> Document {
+pub fn nil<A>() -> Document<A> {
     Document(FormatDesc::Nil)
 }
 
 /// Produces a newline marker.
 #[inline(always)]
-pub fn line() -> Document {
+pub fn line<A>() -> Document<A> {
     Document(FormatDesc::Line)
 }
 
 /// Transforms text into a document.
 #[inline(always)]
-pub fn text(s: impl Into<String>) -> Document {
+pub fn text<A>(s: impl Into<String>) -> Document<A> {
     Document(FormatDesc::Text(s.into()))
 }
 
 /// Indent the given document with `i` spaces.
 #[inline(always)]
-pub fn nest(i: i32, x: Document) -> Document {
-    Document(FormatDesc::Nest(i, Box::new(x.0)))
+pub fn nest<A>(i: i32, x: Document<A>) -> Document<A> {
+    Document(FormatDesc::Nest(i, Box::new(x.into_inner())))
 }
 
 /// Concatenates two documents.
 #[inline(always)]
-pub fn cat(x: Document, y: Document) -> Document {
-    Document(FormatDesc::Cat(Box::new(x.0), Box::new(y.0)))
+pub fn cat<A>(x: Document<A>, y: Document<A>) -> Document<A> {
+    Document(FormatDesc::Cat(Box::new(x.into_inner()), Box::new(y.into_inner())))
 }
 
 /// Marks `x` and `y` as alternative layouts, `x` taking precedence over `y`.
 /// `x` and `y` must flatten to the same layout, i.e.
 /// `x.flatten().to_string() == y.flatten().to_string()`
 #[inline(always)]
-pub(crate) fn union(x: Document, y: Document) -> Document {
-    Document(FormatDesc::Union(Box::new(x.0), Box::new(y.0)))
+pub(crate) fn union<A>(x: Document<A>, y: Document<A>) -> Document<A> {
+    Document(FormatDesc::Union(Box::new(x.into_inner()), Box::new(y.into_inner())))
+}
+
+/// Attaches the annotation `a` to `x`, e.g. a syntax-highlight category later
+/// consumed by [ProcessedFormat::render_annotated] or [ProcessedFormat::ansi].
+/// Annotation boundaries are preserved by [group]/[group_with]: flattening a
+/// document never removes or reorders them, it only collapses [line]s.
+#[inline(always)]
+pub fn annotate<A>(a: A, x: Document<A>) -> Document<A> {
+    Document(FormatDesc::Annot(a, Box::new(x.into_inner())))
+}
+
+/// Produces a document depending on the current used-width `k` (the column layout has
+/// reached so far on the current line). Useful for aligning things relative to where
+/// they started, e.g. hanging indents for function arguments.
+#[inline(always)]
+pub fn column<A: 'static>(f: impl Fn(i32) -> Document<A> + 'static) -> Document<A> {
+    Document(FormatDesc::Column(Rc::new(move |k| f(k).into_inner())))
+}
+
+/// Produces a document depending on the current indentation `i` (the nesting level in
+/// effect at this point in the tree, as set by [nest]).
+#[inline(always)]
+pub fn nesting<A: 'static>(f: impl Fn(i32) -> Document<A> + 'static) -> Document<A> {
+    Document(FormatDesc::Nesting(Rc::new(move |i| f(i).into_inner())))
+}
+
+/// Re-indents `x` so that its continuation lines (after a [line]) land at the column
+/// where `x` itself begins, rather than at the enclosing [nest] level.
+#[inline(always)]
+pub fn align<A: Clone + 'static>(x: Document<A>) -> Document<A> {
+    nesting(move |n| {
+        let x = x.clone();
+        column(move |k| nest(k - n, x.clone()))
+    })
 }
 
-impl std::ops::BitAnd<Document> for Document {
-    type Output = Document;
+impl<A> std::ops::BitAnd<Document<A>> for Document<A> {
+    type Output = Document<A>;
 
     #[inline(always)]
-    fn bitand(self, rhs: Document) -> Self::Output {
+    fn bitand(self, rhs: Document<A>) -> Self::Output {
         cat(self, rhs)
     }
 }
 
-impl std::ops::Add<Document> for Document {
-    type Output = Document;
+impl<A> std::ops::Add<Document<A>> for Document<A> {
+    type Output = Document<A>;
 
     #[inline(always)]
-    fn add(self, rhs: Document) -> Self::Output {
+    fn add(self, rhs: Document<A>) -> Self::Output {
         self & text(" ") & rhs
     }
 }
 
-impl std::ops::Div<Document> for Document {
-    type Output = Document;
+impl<A> std::ops::Div<Document<A>> for Document<A> {
+    type Output = Document<A>;
 
     #[inline(always)]
-    fn div(self, rhs: Document) -> Self::Output {
+    fn div(self, rhs: Document<A>) -> Self::Output {
         self & line() & rhs
     }
 }
 
-impl std::ops::Mul<Document> for Document {
-    type Output = Document;
+impl<A> std::ops::Mul<Document<A>> for Document<A> {
+    type Output = Document<A>;
 
     #[inline(always)]
-    fn mul(self, rhs: Document) -> Self::Output {
-        self & union(text(" "), line()) & rhs
+    fn mul(self, rhs: Document<A>) -> Self::Output {
+        self & group(line()) & rhs
     }
 }
 
-/// Adds the flattened layout (everything compressed on one line) as
-/// an alternative layout to a document.
+/// Adds the flattened layout (everything compressed on one line, a [line] becoming a
+/// single space) as an alternative layout to a document, picked with [Lindig's strict
+/// "fits" scan](https://www.st.cs.uni-saarland.de/publications/files/lindig-strictly-2000.pdf)
+/// rather than by evaluating both layouts in full.
 #[inline(always)]
-pub fn group(x: Document) -> Document {
-    union(x.flatten(), x)
+pub fn group<A>(x: Document<A>) -> Document<A> {
+    group_with(" ", x)
 }
 
-/// Adds the flattened layout (everything compressed on one line, newlines being replace by the `c` string)
-/// as an alternative layout to a document.
+/// Adds the flattened layout (everything compressed on one line, newlines being replaced by the
+/// `c` string) as an alternative layout to a document.
 #[inline(always)]
-pub fn group_with(c: &str, x: Document) -> Document {
-    union(x.flatten_with(c), x)
+pub fn group_with<A>(c: impl Into<String>, x: Document<A>) -> Document<A> {
+    Document(FormatDesc::Group(c.into(), Box::new(x.into_inner())))
 }
 
 /// Convenience function for the common operation of delimiting a document.
 ///
 /// The `x` document will be indented with `i` spaces, and enclosed by the `l` and `r` elements.
 #[inline(always)]
-pub fn bracket(i: i32, l: impl Into<String>, x: Document, r: impl Into<String>) -> Document {
+pub fn bracket<A>(i: i32, l: impl Into<String>, x: Document<A>, r: impl Into<String>) -> Document<A> {
     group(text(l) & nest(i, line() & x) / text(r))
 }
 
 /// Collapses a list of documents according to `op`. If the slice is empty,
 /// returns [nil].
 #[inline(always)]
-pub fn fold(xs: &[impl Format], op: impl FnMut(Document, Document) -> Document) -> Document {
+pub fn fold<A: 'static>(
+    xs: &[impl Format<A>],
+    op: impl FnMut(Document<A>, Document<A>) -> Document<A>,
+) -> Document<A> {
     xs.iter().map(Format::format).reduce(op).unwrap_or(nil())
 }
 
 /// Collapses a list of documents and inserts a space between every element of the slice.
 #[inline(always)]
-pub fn spread(xs: &[impl Format]) -> Document {
+pub fn spread<A: 'static>(xs: &[impl Format<A>]) -> Document<A> {
     fold(xs, |lhs, rhs| lhs + rhs)
 }
 
 /// Collapses a list of documents and inserts a newline between every element of the slice.
 #[inline(always)]
-pub fn stack(xs: &[impl Format]) -> Document {
+pub fn stack<A: 'static>(xs: &[impl Format<A>]) -> Document<A> {
     fold(xs, |lhs, rhs| lhs / rhs)
 }
 
@@ -206,7 +338,7 @@ pub fn stack(xs: &[impl Format]) -> Document {
 /// using a space or a newline between each document
 ///
 /// See page 14 of [A prettier printer](https://homepages.inf.ed.ac.uk/wadler/papers/prettier/prettier.pdf).
-pub fn fill(xs: &[impl Format]) -> Document {
+pub fn fill<A: Clone + 'static>(xs: &[impl Format<A>]) -> Document<A> {
     match &xs[..] {
         [] => nil(),
         [x] => x.format(),